@@ -1,29 +1,159 @@
+// The protocol code uses `format!` uniformly for both templated and plain error
+// messages, so silence the resulting stylistic lint rather than splitting the
+// two apart.
+#![allow(clippy::useless_format)]
+
 extern crate futures;
-extern crate tokio_core;
+extern crate tokio;
 extern crate tokio_io;
 
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::io::{BufWriter, BufReader, BufRead};
-use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::env;
 use std::net::SocketAddr;
 use std::fs::{File, OpenOptions};
 use std::io::Write;
 
 use futures::prelude::*;
-use tokio_core::net::TcpListener;
-use tokio_core::reactor::Core;
+use futures::sync::mpsc;
+use tokio::net::TcpListener;
 use tokio_io::AsyncRead;
 use tokio_io::io::{lines, write_all};
 
+/// How many appended log lines we tolerate before rewriting the log to hold
+/// only live state. Overridable with the third positional CLI argument.
+const DEFAULT_COMPACTION_THRESHOLD: usize = 1024;
+
+/// Number of independently-locked shards the key space is spread across. A
+/// higher count lets more unrelated keys be mutated in parallel at the cost of
+/// a little more memory.
+const NUM_SHARDS: usize = 16;
+
+/// Source of unique per-connection ids used to key `WATCH` subscriptions.
+static NEXT_CONN_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// How many writes a `batch` durability policy buffers before flushing.
+const DEFAULT_BATCH_SIZE: usize = 128;
+
+/// How hard we try to get a write onto stable storage before returning a
+/// success response to the client.
+#[derive(Clone, Copy)]
+enum Durability {
+    /// Buffer writes and let the OS decide when to flush. Fastest, least safe.
+    None,
+    /// Flush the buffer to the OS every `every` writes.
+    Batch { every: usize },
+    /// `flush` and `sync_data` after every write, so a success response means
+    /// the write survived a crash.
+    Always,
+}
+
+/// The shared, long-lived handle to the append-only persist log. Opened once at
+/// startup and written through on every `PUT`/`DEL` instead of reopening the
+/// file each time.
+struct LogWriter {
+    buf: BufWriter<File>,
+    since_flush: usize,
+}
+
+/// What a user is allowed to do once authenticated.
+#[derive(Clone, Copy, PartialEq)]
+enum Permission {
+    /// May issue read commands only; `PUT`/`DEL`/`COMPACT`/`CREATE`/`DROP` are
+    /// denied.
+    ReadOnly,
+    /// Full read/write access.
+    ReadWrite,
+}
+
+/// A credential-table entry loaded at startup.
+struct Credential {
+    password: String,
+    permission: Permission,
+}
+
+/// Mutable authentication state carried by a single connection for the lifetime
+/// of that connection, threaded through `process_line`.
+struct Session {
+    authenticated: bool,
+    permission: Permission,
+    /// Name of the store selected with `USE`, against which this connection's
+    /// `GET`/`PUT`/`DEL` operate.
+    current: Option<String>,
+}
+
+/// A single client's interest in key changes: notifications for keys in `store`
+/// starting with `prefix` are pushed down `tx`.
+struct Subscription {
+    conn_id: usize,
+    store: String,
+    prefix: String,
+    tx: mpsc::UnboundedSender<String>,
+}
+
+/// Per-connection handle threaded through `process_line` so `WATCH` can register
+/// the connection's notification sender and change commands can fan out to it.
+struct Connection {
+    id: usize,
+    tx: mpsc::UnboundedSender<String>,
+}
+
 /// The in-memory database shared amongst all clients.
 ///
-/// This database will be shared via `Rc`, so to mutate the internal map we're
-/// also going to use a `RefCell` for interior mutability.
+/// The database is shared across worker threads via `Arc`, so the key space is
+/// split into `NUM_SHARDS` independently `Mutex`-guarded shards: GETs on
+/// unrelated keys only contend when they hash to the same shard. Note that
+/// PUT/DEL additionally update the single `index` lock (see its docs), so
+/// writes still serialize globally even though point reads scale.
 struct Database {
-    map: RefCell<HashMap<String, String>>,
+    shards: Vec<Mutex<HashMap<String, String>>>,
+    /// Ordered view of the whole key space kept in sync with `shards`, so range
+    /// (`SCAN`) and prefix (`KEYS`) queries can iterate in sorted order without
+    /// scanning every shard.
+    ///
+    /// This is a single lock covering the whole key space, so every `insert`
+    /// and `remove` serializes on it: writes do NOT scale across shards the way
+    /// `get` does (see `shards`). Kept single so range/prefix scans stay a
+    /// cheap global iteration; sharding the index would force a k-way merge on
+    /// every `SCAN`/`KEYS`.
+    index: Mutex<BTreeMap<String, String>>,
     persist_log: String,
+    /// Long-lived writer for `persist_log`, opened once at startup.
+    writer: Mutex<LogWriter>,
+    durability: Durability,
+    /// Number of lines appended to `persist_log` since the last compaction.
+    /// Once this crosses `compaction_threshold` we rewrite the log in place.
+    appended_since_compaction: AtomicUsize,
+    compaction_threshold: usize,
+}
+
+/// The shared server state: a registry of named stores plus the cross-store
+/// concerns (authentication, change subscriptions) and the defaults applied to
+/// any store created at runtime.
+///
+/// Shared across worker threads via `Arc`; the registry itself is behind a
+/// `Mutex` so `CREATE`/`USE`/`DROP` stay thread-safe.
+struct Server {
+    /// All logical stores hosted by this server, keyed by name.
+    stores: Mutex<HashMap<String, Arc<Database>>>,
+    /// Directory holding the `<name>.log` files.
+    dir: String,
+    /// Active `WATCH` subscriptions, consulted on every change to push `NOTIFY`
+    /// lines to interested connections.
+    subscriptions: Mutex<Vec<Subscription>>,
+    /// Credential table loaded at startup, keyed by user name. Empty when no
+    /// credentials file was configured, in which case authentication is off.
+    credentials: HashMap<String, Credential>,
+    /// Whether clients must `AUTH` before issuing data commands.
+    auth_required: bool,
+    /// Durability policy applied to every store.
+    durability: Durability,
+    /// Compaction threshold applied to every store.
+    compaction_threshold: usize,
 }
 
 /// Possible requests our clients can send us
@@ -33,6 +163,15 @@ enum Request {
     Get { key: String },
     Del { key: String },
     Put { key: String, value: String },
+    Compact {},
+    Watch { prefix: String },
+    Unwatch { prefix: String },
+    Scan { start: String, end: String },
+    Keys { prefix: String },
+    Auth { user: String, password: String },
+    Create { name: String },
+    Use { name: String },
+    Drop { name: String },
 }
 
 /// Responses to the `Request` commands above
@@ -42,6 +181,9 @@ enum Response {
     Del { key: String },
     Error { msg: String },
     Message { msg: String },
+    /// A sorted list of `key value` lines terminated by an `END` marker, used
+    /// for the `SCAN` and `KEYS` range queries.
+    Results { matches: Vec<(String, String)> },
 }
 
 fn main() {
@@ -49,20 +191,30 @@ fn main() {
     // set up our TCP listener to accept connections.
     let addr = env::args().nth(1).unwrap_or("127.0.0.1:8080".to_string());
     let addr = addr.parse::<SocketAddr>().unwrap();
-    let _persist_log_path = env::args().nth(2).unwrap_or("db.log".to_string());
-    let mut core = Core::new().unwrap();
-    let handle = core.handle();
-    let listener = TcpListener::bind(&addr, &handle).expect("failed to bind");
+    let data_dir = env::args().nth(2).unwrap_or("data".to_string());
+    let compaction_threshold = env::args()
+        .nth(3)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_COMPACTION_THRESHOLD);
+    let durability = match env::args().nth(4).unwrap_or("batch".to_string()).as_ref() {
+        "none" => Durability::None,
+        "always" => Durability::Always,
+        _ => Durability::Batch { every: DEFAULT_BATCH_SIZE },
+    };
+    let credentials = match env::args().nth(5) {
+        Some(path) => load_credentials(&path),
+        None => HashMap::new(),
+    };
+    let listener = TcpListener::bind(&addr).expect("failed to bind");
     println!("Listening on: {}", addr);
 
     // Create the shared state of this server that will be shared amongst all
-    // clients. We populate the initial database and then create the `Database`
-    // structure. Note the usage of `Rc` here which will be used to ensure that
-    // each independently spawned client will have a reference to the in-memory
-    // database.
-    let db = init_database(_persist_log_path);
+    // clients. We scan the data directory for existing stores and replay each
+    // one. Note the usage of `Arc` here which will be used to ensure that each
+    // independently spawned client will have a reference to the registry.
+    let server = init_server(data_dir, compaction_threshold, durability, credentials);
 
-    let done = listener.incoming().for_each(move |(socket, _addr)| {
+    let done = listener.incoming().for_each(move |socket| {
         // As with many other small examples, the first thing we'll do is
         // *split* this TCP stream into two separately owned halves. This'll
         // allow us to work with the read and write halves independently.
@@ -78,97 +230,513 @@ fn main() {
         // keyword on the closure here which moves ownership of the reference
         // into the closure, which we'll need for spawning the client below.
         //
-        // The `map` function here means that we'll run some code for all
-        // requests (lines) we receive from the client. The actual handling here
-        // is pretty simple, first we parse the request and if it's valid we
-        // generate a response based on the values in the database.
-        let db = db.clone();
-        let responses = lines.map(move |line| { db.process_line(&line, true) });
-
-        // At this point `responses` is a stream of `Response` types which we
-        // now want to write back out to the client. To do that we use
-        // `Stream::fold` to perform a loop here, serializing each response and
-        // then writing it out to the client.
-        let writes = responses.fold(writer, |writer, response| {
-            let mut answer = response.serialize();
+        // Everything this connection sends back — request replies *and* pushed
+        // `NOTIFY` lines — goes through a single outbound channel so we only ever
+        // write from one place. `out_tx` is what `WATCH` registers for async
+        // notifications; the reader task below owns the other clone and drops it
+        // (and the connection's subscriptions) the moment the request stream
+        // ends, which lets the writer terminate on a clean FIN rather than
+        // parking forever.
+        let (out_tx, out_rx) = mpsc::unbounded::<String>();
+        let conn = Connection { id: NEXT_CONN_ID.fetch_add(1, Ordering::SeqCst), tx: out_tx.clone() };
+        let conn_id = conn.id;
+
+        let server = server.clone();
+        let cleanup_server = server.clone();
+
+        // Per-connection session state. When authentication is disabled the
+        // session starts already authenticated with full privileges; otherwise
+        // it must be unlocked with `AUTH`. A connection defaults to the
+        // `default` store when one exists.
+        let mut session = Session {
+            authenticated: !server.auth_required,
+            permission: Permission::ReadWrite,
+            current: if server.has_store("default") { Some("default".to_string()) } else { None },
+        };
+
+        // Reader task: handle each request line and push the serialized reply
+        // onto the outbound channel. When `lines` ends (clean FIN) or errors this
+        // future resolves, we drop this connection's subscriptions and let the
+        // captured senders (`conn` and `reader_tx`) fall out of scope.
+        let reader_tx = out_tx.clone();
+        let reads = lines.for_each(move |line| {
+            let mut answer = server.process_line(&line, &conn, &mut session).serialize();
             answer.push('\n');
             print!("{}", answer);
-            write_all(writer, answer.into_bytes()).map(|(w, _)| w)
+            let _ = reader_tx.unbounded_send(answer);
+            Ok(())
         });
+        // Drop the spare sender so the only remaining ones live in the reader
+        // task and the subscription registry.
+        drop(out_tx);
+        let reader = reads.then(move |_| {
+            cleanup_server.drop_connection(conn_id);
+            Ok(())
+        });
+        tokio::spawn(reader);
 
-        // Like with other small servers, we'll `spawn` this client to ensure it
-        // runs concurrently with all other clients, for now ignoring any errors
-        // that we see.
-        let msg = writes.then(move |_| Ok(()));
-        handle.spawn(msg);
+        // Writer task: drain the outbound channel to the socket. It completes
+        // once every sender is gone, which the reader task guarantees on
+        // disconnect.
+        let writes = out_rx.fold(writer, |writer, answer| {
+            write_all(writer, answer.into_bytes())
+                .map(|(w, _)| w)
+                .map_err(|_| ())
+        });
+        tokio::spawn(writes.then(|_| Ok(())));
         Ok(())
-    });
+    })
+    .map_err(|e| println!("server error: {:?}", e));
 
-    core.run(done).unwrap();
+    // Drive the listener on a multi-worker runtime so clients spawned above run
+    // truly in parallel across the worker pool rather than on a single reactor.
+    tokio::run(done);
 }
 
-fn init_database(persist_path: String) -> Rc<Database> {
-    let ret = Rc::new(Database {
-        map: RefCell::new(HashMap::new()),
-        persist_log:  persist_path,
-    });
-    let reader = BufReader::new(File::open(ret.persist_log.clone()).expect("Unable to open persist logi file"));
+/// Load a credential table from a file with one `user password [ro|rw]` entry
+/// per line. A missing permission column defaults to read/write. Blank lines
+/// and `#` comments are ignored.
+fn load_credentials(path: &str) -> HashMap<String, Credential> {
+    let mut table = HashMap::new();
+    let reader = BufReader::new(File::open(path).expect("Unable to open credentials file"));
     for line in reader.lines() {
-        let response = ret.process_line(&line.unwrap(), false);
-        println!("{}", response.serialize());
+        let line = line.unwrap();
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(3, ' ');
+        let user = match parts.next() {
+            Some(user) => user.to_string(),
+            None => continue,
+        };
+        let password = parts.next().unwrap_or("").to_string();
+        let permission = match parts.next() {
+            Some("ro") => Permission::ReadOnly,
+            _ => Permission::ReadWrite,
+        };
+        table.insert(user, Credential { password, permission });
     }
-    println!("Persistent storage read");
-    return ret;
+    table
 }
 
-fn append_line( filename: String, line: String ) -> bool {
-    let file = OpenOptions::new()
-                           .append(true)
-                           .create(true)
-                           .open(filename)
-                           .expect("Unable to open persistent log file");
-    let mut writer = BufWriter::new(file);
-    return match writeln!(writer, "{}", line) {
-        Ok(_) =>  true,
-        Err(_) => false,
-    };
+fn init_server(dir: String, compaction_threshold: usize, durability: Durability, credentials: HashMap<String, Credential>) -> Arc<Server> {
+    std::fs::create_dir_all(&dir).expect("Unable to create data directory");
+    let mut stores = HashMap::new();
+    // Discover and replay every `<name>.log` in the data directory.
+    for entry in std::fs::read_dir(&dir).expect("Unable to read data directory") {
+        let path = entry.expect("Unable to read directory entry").path();
+        if path.extension().and_then(|e| e.to_str()) != Some("log") {
+            continue;
+        }
+        let name = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let log_path = path.to_str().expect("non-utf8 log path").to_string();
+        stores.insert(name.clone(), Database::open(log_path, durability, compaction_threshold));
+        println!("Replayed store {}", name);
+    }
+    // Ensure there is always a `default` store so the server is usable without
+    // an explicit `CREATE`/`USE`.
+    if !stores.contains_key("default") {
+        let log_path = format!("{}/default.log", dir);
+        stores.insert("default".to_string(), Database::open(log_path, durability, compaction_threshold));
+    }
+    println!("Persistent storage read");
+    Arc::new(Server {
+        stores: Mutex::new(stores),
+        dir,
+        subscriptions: Mutex::new(Vec::new()),
+        auth_required: !credentials.is_empty(),
+        credentials,
+        durability,
+        compaction_threshold,
+    })
 }
 
 impl Database {
-    fn process_line(&self, line: &String, write_log: bool) -> Response { 
+    /// Open a store backed by `persist_log`, replaying it into memory. The log
+    /// is created if it does not yet exist.
+    fn open(persist_log: String, durability: Durability, compaction_threshold: usize) -> Arc<Database> {
+        let mut shards = Vec::with_capacity(NUM_SHARDS);
+        for _ in 0..NUM_SHARDS {
+            shards.push(Mutex::new(HashMap::new()));
+        }
+        let log_file = OpenOptions::new()
+                                   .append(true)
+                                   .create(true)
+                                   .open(&persist_log)
+                                   .expect("Unable to open persistent log file");
+        let ret = Arc::new(Database {
+            shards,
+            index: Mutex::new(BTreeMap::new()),
+            persist_log,
+            writer: Mutex::new(LogWriter { buf: BufWriter::new(log_file), since_flush: 0 }),
+            durability,
+            appended_since_compaction: AtomicUsize::new(0),
+            compaction_threshold,
+        });
+        ret.replay();
+        ret
+    }
+
+    /// Apply every `PUT`/`DEL` line already in the log to the in-memory state
+    /// without re-logging it.
+    fn replay(&self) {
+        let reader = BufReader::new(File::open(&self.persist_log).expect("Unable to open persist log file"));
+        for line in reader.lines() {
+            match Request::parse(&line.unwrap()) {
+                Ok(Request::Put { key, value }) => self.insert(key, value),
+                Ok(Request::Del { key }) => self.remove(&key),
+                _ => {}
+            }
+        }
+    }
+
+    /// Append a line to an already-locked persist-log writer, honouring the
+    /// configured durability policy. Returns an error only when the level of
+    /// durability the operator asked for could not be met.
+    fn append_locked(&self, w: &mut LogWriter, line: &str) -> Result<(), String> {
+        writeln!(w.buf, "{}", line).map_err(|e| format!("error writing to persist log: {}", e))?;
+        match self.durability {
+            Durability::None => {}
+            Durability::Batch { every } => {
+                w.since_flush += 1;
+                if w.since_flush >= every {
+                    w.buf.flush().map_err(|e| format!("error flushing persist log: {}", e))?;
+                    w.since_flush = 0;
+                }
+            }
+            Durability::Always => {
+                w.buf.flush().map_err(|e| format!("error flushing persist log: {}", e))?;
+                w.buf.get_ref().sync_data().map_err(|e| format!("error syncing persist log: {}", e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Log a `PUT` and apply it to memory while holding the `writer` lock, so
+    /// the durable append and the in-memory update are atomic with respect to
+    /// `compact` (which snapshots memory under the same lock). Without this the
+    /// snapshot could land between the append and the insert and drop the write.
+    fn put(&self, key: String, value: String) -> Result<(), String> {
+        let mut w = self.writer.lock().unwrap();
+        self.append_locked(&mut w, &format!("PUT {} {}", key, value))?;
+        self.insert(key, value);
+        Ok(())
+    }
+
+    /// Log a `DEL` and apply it to memory atomically with respect to `compact`;
+    /// see [`Database::put`].
+    fn delete(&self, key: &str) -> Result<(), String> {
+        let mut w = self.writer.lock().unwrap();
+        self.append_locked(&mut w, &format!("DEL {}", key))?;
+        self.remove(key);
+        Ok(())
+    }
+
+    /// Record that one line was appended to the log, triggering a compaction
+    /// once the configured threshold is reached.
+    fn note_appended(&self) {
+        let previous = self.appended_since_compaction.fetch_add(1, Ordering::SeqCst);
+        if previous + 1 >= self.compaction_threshold {
+            if let Err(e) = self.compact() {
+                println!("error: compaction failed: {}", e);
+            }
+        }
+    }
+
+    /// Shard index a given key lives in.
+    fn shard_for(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() as usize) % self.shards.len()
+    }
+
+    /// Rewrite `persist_log` so it holds only one `PUT` line per live key.
+    ///
+    /// We write the surviving state into `<persist_log>.tmp`, flush it, then atomically
+    /// rename it over the real log so a crash mid-compaction leaves the original
+    /// log untouched rather than a half-written file.
+    fn compact(&self) -> Result<(), String> {
+        let tmp_path = format!("{}.tmp", self.persist_log);
+        // Hold the writer lock across the whole rewrite so no append can slip in
+        // between the snapshot and the rename and then be lost.
+        let mut w = self.writer.lock().unwrap();
+        w.buf.flush().map_err(|e| format!("error flushing persist log: {}", e))?;
+        {
+            let file = OpenOptions::new()
+                                   .write(true)
+                                   .create(true)
+                                   .truncate(true)
+                                   .open(&tmp_path)
+                                   .map_err(|e| format!("unable to open {}: {}", tmp_path, e))?;
+            let mut writer = BufWriter::new(file);
+            for shard in self.shards.iter() {
+                for (key, value) in shard.lock().unwrap().iter() {
+                    writeln!(writer, "PUT {} {}", key, value)
+                        .map_err(|e| format!("error writing compacted log: {}", e))?;
+                }
+            }
+            writer.flush().map_err(|e| format!("error flushing compacted log: {}", e))?;
+        }
+        std::fs::rename(&tmp_path, &self.persist_log)
+            .map_err(|e| format!("error replacing persist log: {}", e))?;
+        // Point the long-lived writer at the freshly-renamed log; the old handle
+        // referred to the now-unlinked file.
+        let log_file = OpenOptions::new()
+                                   .append(true)
+                                   .create(true)
+                                   .open(&self.persist_log)
+                                   .map_err(|e| format!("error reopening persist log: {}", e))?;
+        w.buf = BufWriter::new(log_file);
+        w.since_flush = 0;
+        self.appended_since_compaction.store(0, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) {
+        self.shards[self.shard_for(key)].lock().unwrap().remove(key);
+        self.index.lock().unwrap().remove(key);
+    }
+
+    fn insert(&self, key: String, value: String ) {
+        let shard = self.shard_for(&key);
+        self.shards[shard].lock().unwrap().insert(key.clone(), value.clone());
+        self.index.lock().unwrap().insert(key, value);
+    }
+
+    /// Inclusive range scan over the ordered index. An inverted range
+    /// (`start > end`) matches nothing rather than panicking on the
+    /// `BTreeMap::range` contract.
+    fn scan(&self, start: &str, end: &str) -> Vec<(String, String)> {
+        if start > end {
+            return Vec::new();
+        }
+        self.index
+            .lock()
+            .unwrap()
+            .range(start.to_string()..=end.to_string())
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    /// Every key/value whose key starts with `prefix`, in sorted order.
+    fn keys(&self, prefix: &str) -> Vec<(String, String)> {
+        self.index
+            .lock()
+            .unwrap()
+            .range(prefix.to_string()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    fn get(&self, key: &str) -> Result<String, String> {
+        return match self.shards[self.shard_for(key)].lock().unwrap().get(key) {
+            Some(value) => Ok(value.to_string()),
+            None => Err("Value not found".to_string())
+        };
+    }
+}
+
+impl Server {
+    /// Whether a store with the given name exists.
+    fn has_store(&self, name: &str) -> bool {
+        self.stores.lock().unwrap().contains_key(name)
+    }
+
+    /// The store the session currently has selected, or an error response the
+    /// caller should return when no usable store is selected.
+    fn current_store(&self, session: &Session) -> Result<Arc<Database>, Response> {
+        let name = match session.current {
+            Some(ref name) => name,
+            None => return Err(Response::Error { msg: format!("no database selected") }),
+        };
+        match self.stores.lock().unwrap().get(name) {
+            Some(db) => Ok(db.clone()),
+            None => Err(Response::Error { msg: format!("no database {}", name) }),
+        }
+    }
+
+    /// Register a connection's interest in keys of `store` starting with
+    /// `prefix`.
+    fn watch(&self, conn_id: usize, store: String, prefix: String, tx: mpsc::UnboundedSender<String>) {
+        self.subscriptions.lock().unwrap().push(Subscription { conn_id, store, prefix, tx });
+    }
+
+    /// Remove a single `prefix` subscription for a connection on `store`.
+    fn unwatch(&self, conn_id: usize, store: &str, prefix: &str) {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .retain(|s| !(s.conn_id == conn_id && s.store == store && s.prefix == prefix));
+    }
+
+    /// Drop every subscription belonging to a connection that has gone away.
+    fn drop_connection(&self, conn_id: usize) {
+        self.subscriptions.lock().unwrap().retain(|s| s.conn_id != conn_id);
+    }
+
+    /// Push a change to every subscriber watching `store` whose prefix matches
+    /// `key`, pruning any whose receiver has already been dropped.
+    fn fan_out(&self, store: &str, key: &str, line: String) {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .retain(|s| {
+                if s.store == store && key.starts_with(&s.prefix) {
+                    s.tx.unbounded_send(line.clone()).is_ok()
+                } else {
+                    true
+                }
+            });
+    }
+
+    fn notify_put(&self, store: &str, key: &str, value: &str) {
+        self.fan_out(store, key, format!("NOTIFY {} {}", key, value));
+    }
+
+    fn notify_del(&self, store: &str, key: &str) {
+        self.fan_out(store, key, format!("NOTIFY-DEL {}", key));
+    }
+
+    fn process_line(&self, line: &str, conn: &Connection, session: &mut Session) -> Response {
             let request = match Request::parse(line) {
                 Ok(req) => req,
                 Err(e) => return Response::Error { msg: e },
             };
 
-            // TODO: How to make a shared state for persist log file handler?
+            // AUTH, PING and EXIT are always available; everything else needs an
+            // authenticated session, and mutating commands need write access.
+            match request {
+                Request::Auth { .. } | Request::Ping { .. } | Request::Exit { } => {}
+                _ if !session.authenticated => {
+                    return Response::Error { msg: format!("not authenticated") };
+                }
+                Request::Put { .. } | Request::Del { .. } | Request::Compact { }
+                | Request::Create { .. } | Request::Drop { .. }
+                    if session.permission != Permission::ReadWrite =>
+                {
+                    return Response::Error { msg: format!("permission denied") };
+                }
+                _ => {}
+            }
+
             match request {
+                Request::Auth { user, password } => {
+                    match self.credentials.get(&user) {
+                        Some(cred) if cred.password == password => {
+                            session.authenticated = true;
+                            session.permission = cred.permission;
+                            Response::Message { msg: format!("authenticated as {}", user) }
+                        }
+                        _ => Response::Error { msg: format!("authentication failed") },
+                    }
+                }
+                Request::Create { name } => {
+                    let mut stores = self.stores.lock().unwrap();
+                    if stores.contains_key(&name) {
+                        return Response::Error { msg: format!("database {} already exists", name) };
+                    }
+                    let log_path = format!("{}/{}.log", self.dir, name);
+                    stores.insert(name.clone(), Database::open(log_path, self.durability, self.compaction_threshold));
+                    Response::Message { msg: format!("created {}", name) }
+                }
+                Request::Use { name } => {
+                    if self.stores.lock().unwrap().contains_key(&name) {
+                        session.current = Some(name.clone());
+                        Response::Message { msg: format!("using {}", name) }
+                    } else {
+                        Response::Error { msg: format!("no database {}", name) }
+                    }
+                }
+                Request::Drop { name } => {
+                    if self.stores.lock().unwrap().remove(&name).is_none() {
+                        return Response::Error { msg: format!("no database {}", name) };
+                    }
+                    let log_path = format!("{}/{}.log", self.dir, name);
+                    let _ = std::fs::remove_file(&log_path);
+                    if session.current.as_ref() == Some(&name) {
+                        session.current = None;
+                    }
+                    Response::Message { msg: format!("dropped {}", name) }
+                }
                 Request::Get { key } => {
-                    match self.get(&key) {
+                    let db = match self.current_store(session) {
+                        Ok(db) => db,
+                        Err(resp) => return resp,
+                    };
+                    match db.get(&key) {
                         Ok(value) => Response::Value { key, value: value.clone() },
                         Err(_error) => Response::Error { msg: format!("no key {}", key) },
                     }
                 }
                 Request::Del { key } => {
-                    if write_log {
-                        match append_line( self.persist_log.clone(), format!("DEL {}", key)) {
-                            false => return Response::Error{ msg: format!("Error writing to persist log")},   
-                            true => {},
-                        }
+                    let db = match self.current_store(session) {
+                        Ok(db) => db,
+                        Err(resp) => return resp,
+                    };
+                    if let Err(e) = db.delete(&key) {
+                        return Response::Error { msg: e };
                     }
-                    self.remove(&key);
+                    db.note_appended();
+                    self.notify_del(session.current.as_ref().unwrap(), &key);
                     Response::Del { key }
                 }
                 Request::Put { key, value } => {
-                    if write_log {
-                        match append_line( self.persist_log.clone(), format!("PUT {} {}", key, value)) {
-                            false => return Response::Error{ msg: format!("Error writing to persist log")},   
-                            true => {},
-                        }
+                    let db = match self.current_store(session) {
+                        Ok(db) => db,
+                        Err(resp) => return resp,
+                    };
+                    if let Err(e) = db.put(key.clone(), value.clone()) {
+                        return Response::Error { msg: e };
                     }
-                    self.insert(key.clone(), value.clone());
+                    db.note_appended();
+                    self.notify_put(session.current.as_ref().unwrap(), &key, &value);
                     Response::Put { key, value }
                 }
+                Request::Compact { } => {
+                    let db = match self.current_store(session) {
+                        Ok(db) => db,
+                        Err(resp) => return resp,
+                    };
+                    match db.compact() {
+                        Ok(()) => Response::Message { msg: format!("compacted") },
+                        Err(e) => Response::Error { msg: e },
+                    }
+                }
+                Request::Scan { start, end } => {
+                    let db = match self.current_store(session) {
+                        Ok(db) => db,
+                        Err(resp) => return resp,
+                    };
+                    Response::Results { matches: db.scan(&start, &end) }
+                }
+                Request::Keys { prefix } => {
+                    let db = match self.current_store(session) {
+                        Ok(db) => db,
+                        Err(resp) => return resp,
+                    };
+                    Response::Results { matches: db.keys(&prefix) }
+                }
+                Request::Watch { prefix } => {
+                    let store = match session.current {
+                        Some(ref name) => name.clone(),
+                        None => return Response::Error { msg: format!("no database selected") },
+                    };
+                    self.watch(conn.id, store.clone(), prefix.clone(), conn.tx.clone());
+                    Response::Message { msg: format!("watching {}", prefix) }
+                }
+                Request::Unwatch { prefix } => {
+                    let store = match session.current {
+                        Some(ref name) => name.clone(),
+                        None => return Response::Error { msg: format!("no database selected") },
+                    };
+                    self.unwatch(conn.id, &store, &prefix);
+                    Response::Message { msg: format!("unwatching {}", prefix) }
+                }
                 Request::Ping { msg } => {
                     Response::Message { msg: format!("PONG: {}", msg) }
                 }
@@ -177,21 +745,6 @@ impl Database {
                 }
             }
     }
-
-    fn remove(&self, key: &String) {
-        self.map.borrow_mut().remove(key);
-    }
-
-    fn insert(&self, key: String, value: String ) {
-        self.map.borrow_mut().insert(key, value);
-    }
-    
-    fn get(&self, key: &String) -> Result<String, String> {
-        return match self.map.borrow().get(key) {
-            Some(value) => Ok(value.to_string()),
-            None => Err("Value not found".to_string())
-        };
-    }
 }
 
 impl Request {
@@ -231,7 +784,95 @@ impl Request {
                 Ok(Request::Del { key: key.to_string() })
             }
             "PING" => {
-                Ok(Request::Ping { msg: format!("{}", parts.next().unwrap_or("").to_string()) })
+                Ok(Request::Ping { msg: format!("{}", parts.next().unwrap_or("")) })
+            }
+            "AUTH" => {
+                let user = match parts.next() {
+                    Some(user) => user,
+                    None => return Err(format!("AUTH must be followed by a user and password")),
+                };
+                let password = match parts.next() {
+                    Some(password) => password,
+                    None => return Err(format!("AUTH needs a password")),
+                };
+                Ok(Request::Auth { user: user.to_string(), password: password.to_string() })
+            }
+            "SCAN" => {
+                let start = match parts.next() {
+                    Some(start) => start,
+                    None => return Err(format!("SCAN must be followed by a start and end key")),
+                };
+                let end = match parts.next() {
+                    Some(end) => end,
+                    None => return Err(format!("SCAN needs an end key")),
+                };
+                Ok(Request::Scan { start: start.to_string(), end: end.to_string() })
+            }
+            "KEYS" => {
+                let prefix = match parts.next() {
+                    Some(prefix) => prefix,
+                    None => return Err(format!("KEYS must be followed by a key prefix")),
+                };
+                if parts.next().is_some() {
+                    return Err(format!("KEYS's prefix must not be followed by anything"))
+                }
+                Ok(Request::Keys { prefix: prefix.to_string() })
+            }
+            "WATCH" => {
+                let prefix = match parts.next() {
+                    Some(prefix) => prefix,
+                    None => return Err(format!("WATCH must be followed by a key prefix")),
+                };
+                if parts.next().is_some() {
+                    return Err(format!("WATCH's prefix must not be followed by anything"))
+                }
+                Ok(Request::Watch { prefix: prefix.to_string() })
+            }
+            "UNWATCH" => {
+                let prefix = match parts.next() {
+                    Some(prefix) => prefix,
+                    None => return Err(format!("UNWATCH must be followed by a key prefix")),
+                };
+                if parts.next().is_some() {
+                    return Err(format!("UNWATCH's prefix must not be followed by anything"))
+                }
+                Ok(Request::Unwatch { prefix: prefix.to_string() })
+            }
+            "COMPACT" => {
+                if parts.next().is_some() {
+                    return Err(format!("COMPACT takes no arguments"))
+                }
+                Ok(Request::Compact {})
+            }
+            "CREATE" => {
+                let name = match parts.next() {
+                    Some(name) => name,
+                    None => return Err(format!("CREATE must be followed by a name")),
+                };
+                if parts.next().is_some() {
+                    return Err(format!("CREATE's name must not be followed by anything"))
+                }
+                Ok(Request::Create { name: name.to_string() })
+            }
+            "USE" => {
+                let name = match parts.next() {
+                    Some(name) => name,
+                    None => return Err(format!("USE must be followed by a name")),
+                };
+                if parts.next().is_some() {
+                    return Err(format!("USE's name must not be followed by anything"))
+                }
+                Ok(Request::Use { name: name.to_string() })
+            }
+            "DROP" => {
+                let name = match parts.next() {
+                    Some(name) => name,
+                    None => return Err(format!("DROP must be followed by a name")),
+                };
+                if parts.next().is_some() {
+                    return Err(format!("DROP's name must not be followed by anything"))
+                }
+                Ok(Request::Drop { name: name.to_string() })
             }
             "EXIT" | "QUIT" => {
                 Ok(Request::Exit {})
@@ -259,6 +900,14 @@ impl Response {
             Response::Error { ref msg } => {
                 format!("error: {}", msg)
             }
+            Response::Results { ref matches } => {
+                let mut out = String::new();
+                for (key, value) in matches.iter() {
+                    out.push_str(&format!("{} {}\n", key, value));
+                }
+                out.push_str("END");
+                out
+            }
         }
     }
 }